@@ -1,105 +1,118 @@
-#![feature(stdsimd)]
-
+extern crate rand;
 extern crate smallvec;
 
+use rand::Rng;
 use smallvec::SmallVec;
 use std::cmp::Ordering;
 use std::fmt;
 use std::iter;
-use std::simd::{m16x16, m1x16, u16x16};
 
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
-struct CompositeId {
+pub struct CompositeId {
     entries: SmallVec<[Id; 1]>,
 }
 
 #[derive(Copy, Clone)]
 struct Id {
-    entries: u16x16,
+    entries: [u16; 16],
     len: u8,
+    site: Option<Site>,
+}
+
+/// Tie-breaker stamped onto an `Id` by the generating replica, compared
+/// only after the positional `entries` compare equal.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct Site {
+    replica_id: u16,
+    seq: u32,
+}
+
+/// Generates monotonically increasing `Site` tags for one replica; reuse
+/// the same `Replica` across calls.
+#[derive(Clone, Debug)]
+pub struct Replica {
+    id: u16,
+    next_seq: u32,
+}
+
+impl Replica {
+    pub fn new(id: u16) -> Self {
+        Self { id, next_seq: 0 }
+    }
+
+    fn stamp(&mut self) -> Site {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Site {
+            replica_id: self.id,
+            seq,
+        }
+    }
+}
+
+/// Beyond `len` significant lanes, substitute `fill` (0 for a lower bound,
+/// `max` for an upper bound) so comparisons and arithmetic see the same
+/// padding `between_with_max`'s chained iterators assume.
+fn masked(entries: [u16; 16], len: u8, fill: u16) -> [u16; 16] {
+    let mut out = entries;
+    for slot in out.iter_mut().skip(len as usize) {
+        *slot = fill;
+    }
+    out
+}
+
+/// Allocation strategy for `CompositeId::between_with_strategy`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    /// The original deterministic midpoint split.
+    Midpoint,
+    /// LSEQ-style boundary bias, to avoid linear depth growth under
+    /// repeated front- or back-loaded inserts.
+    Lseq,
+}
+
+/// Which edge of a depth's free interval `Strategy::Lseq` biases new ids
+/// towards, cached per depth in `LseqState` so interleaved inserts keep
+/// landing on the same side.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Boundary {
+    Plus,
+    Minus,
 }
 
-static MASKS: [m1x16; 16] = [
-    m1x16::new(
-        false, true, true, true, true, true, true, true, true, true, true, true, true, true, true,
-        true,
-    ),
-    m1x16::new(
-        false, false, true, true, true, true, true, true, true, true, true, true, true, true, true,
-        true,
-    ),
-    m1x16::new(
-        false, false, false, true, true, true, true, true, true, true, true, true, true, true,
-        true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, true, true, true, true, true, true, true, true, true, true,
-        true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, true, true, true, true, true, true, true, true, true,
-        true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, true, true, true, true, true, true, true, true,
-        true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, true, true, true, true, true, true, true,
-        true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, true, true, true, true, true, true,
-        true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, true, true, true, true,
-        true, true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, true, true, true,
-        true, true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, false, true, true,
-        true, true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, false, false, true,
-        true, true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, false, false, false,
-        true, true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, false, false, false,
-        false, true, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, false, false, false,
-        false, false, true,
-    ),
-    m1x16::new(
-        false, false, false, false, false, false, false, false, false, false, false, false, false,
-        false, false, false,
-    ),
-];
+/// Exponent of `Strategy::Lseq`'s offset bound at depth 0: the random offset
+/// from the chosen boundary is at most `2^(LSEQ_START_BASE + depth)`, so the
+/// bound doubles with depth. The digit space itself is `0..=max` at every
+/// depth; it does not grow with depth.
+const LSEQ_START_BASE: u32 = 4;
+
+/// Per-depth boundary cache for `Strategy::Lseq`; reuse across calls from
+/// the same generator.
+#[derive(Clone, Debug, Default)]
+pub struct LseqState {
+    boundaries: Vec<Option<Boundary>>,
+}
+
+impl LseqState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
 
 impl CompositeId {
-    fn new(value: u16) -> Self {
+    pub fn new(value: u16) -> Self {
         let mut entries = SmallVec::new();
         entries.push(Id::new(value));
         Self { entries }
     }
 
-    fn between_with_max(a: &Self, b: &Self, max: u16) -> Self {
+    pub fn between_with_max(a: &Self, b: &Self, max: u16) -> Self {
         debug_assert!(a < b);
         let a = a.entries.iter().cloned().chain(iter::repeat(Id::new(0)));
         let b = b.entries.iter().cloned().chain(iter::repeat(Id::new(max)));
         let mut entries = SmallVec::new();
         for (a, b) in a.zip(b) {
-            if a == b {
+            if a.same_position(&b) {
                 entries.push(a);
             } else if let Ok(middle) = Id::between_with_max(a, b, max) {
                 entries.push(middle);
@@ -110,36 +123,257 @@ impl CompositeId {
         }
         Self { entries }
     }
+
+    /// Like `between_with_max`, but allocates according to `strategy`.
+    pub fn between_with_strategy<R: Rng>(
+        a: &Self,
+        b: &Self,
+        max: u16,
+        strategy: Strategy,
+        rng: &mut R,
+        state: &mut LseqState,
+    ) -> Self {
+        debug_assert!(a < b);
+        if let Strategy::Midpoint = strategy {
+            return Self::between_with_max(a, b, max);
+        }
+
+        let a_entries = a.entries.iter().cloned().chain(iter::repeat(Id::new(0)));
+        let b_entries = b.entries.iter().cloned().chain(iter::repeat(Id::new(max)));
+        let mut entries = SmallVec::new();
+        for (depth, (a, b)) in a_entries.zip(b_entries).enumerate() {
+            if a.same_position(&b) {
+                entries.push(a);
+            } else if let Ok(middle) = Id::between_lseq(a, b, depth, rng, state) {
+                entries.push(middle);
+                break;
+            } else {
+                entries.push(a);
+            }
+        }
+        Self { entries }
+    }
+
+    /// Serializes this id so lexicographic byte comparison matches `Ord`:
+    /// `a < b` iff `a.to_order_preserving_bytes() <
+    /// b.to_order_preserving_bytes()`. Each lane's value is followed by a
+    /// "more lanes" marker byte (value before marker, so it's always
+    /// compared first), then the level's site tag, then a "more levels"
+    /// marker byte.
+    pub fn to_order_preserving_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let level_count = self.entries.len();
+        for (level, id) in self.entries.iter().enumerate() {
+            for i in 0..id.len {
+                bytes.extend_from_slice(&id.entries[i as usize].to_be_bytes());
+                bytes.push(if i + 1 < id.len { 1 } else { 0 });
+            }
+            match id.site {
+                Some(site) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&site.replica_id.to_be_bytes());
+                    bytes.extend_from_slice(&site.seq.to_be_bytes());
+                }
+                None => bytes.push(0),
+            }
+            bytes.push(if level + 1 < level_count { 1 } else { 0 });
+        }
+        bytes
+    }
+
+    /// Inverse of `to_order_preserving_bytes`.
+    pub fn from_bytes(mut bytes: &[u8]) -> Self {
+        let mut entries = SmallVec::new();
+        loop {
+            let mut values = [0u16; 16];
+            let mut len = 0u8;
+            loop {
+                let value = u16::from_be_bytes([bytes[0], bytes[1]]);
+                let more_lanes = bytes[2];
+                values[len as usize] = value;
+                len += 1;
+                bytes = &bytes[3..];
+                if more_lanes == 0 {
+                    break;
+                }
+            }
+
+            let has_site = bytes[0];
+            bytes = &bytes[1..];
+            let site = if has_site == 0 {
+                None
+            } else {
+                let replica_id = u16::from_be_bytes([bytes[0], bytes[1]]);
+                let seq = u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+                bytes = &bytes[6..];
+                Some(Site { replica_id, seq })
+            };
+
+            entries.push(Id {
+                entries: values,
+                len,
+                site,
+            });
+
+            let more_levels = bytes[0];
+            bytes = &bytes[1..];
+            if more_levels == 0 {
+                break;
+            }
+        }
+        Self { entries }
+    }
+
+    /// Like `between_with_max`, but stamps the result with `replica`'s site tag.
+    pub fn between_with_max_for_replica(
+        a: &Self,
+        b: &Self,
+        max: u16,
+        replica: &mut Replica,
+    ) -> Self {
+        let mut result = Self::between_with_max(a, b, max);
+        if let Some(last) = result.entries.last_mut() {
+            last.site = Some(replica.stamp());
+        }
+        result
+    }
+
+    /// Allocates `n` strictly-increasing ids spread evenly across `(a, b)`
+    /// in a single pass, rather than shrinking the interval `n` times over.
+    pub fn between_n_with_max(a: &Self, b: &Self, n: usize, max: u16) -> Vec<Self> {
+        debug_assert!(a < b);
+        if n == 0 {
+            return Vec::new();
+        }
+
+        let a_entries = a.entries.iter().cloned().chain(iter::repeat(Id::new(0)));
+        let b_entries = b.entries.iter().cloned().chain(iter::repeat(Id::new(max)));
+        let mut prefix: SmallVec<[Id; 1]> = SmallVec::new();
+        for (a_id, b_id) in a_entries.zip(b_entries) {
+            if a_id.same_position(&b_id) {
+                prefix.push(a_id);
+                continue;
+            }
+
+            let a_val = a_id.entries()[0] as u32;
+            let b_val = b_id.entries()[0] as u32;
+            let width = b_val - a_val;
+            if width < 2 {
+                // No room at this depth; try one level deeper.
+                prefix.push(a_id);
+                continue;
+            }
+
+            let placed = width.saturating_sub(1).min(n as u32) as usize;
+            let mut result = Vec::with_capacity(n);
+            for i in 1..=placed {
+                let value = a_val + (i as u32 * width) / (placed as u32 + 1);
+                let mut entries = prefix.clone();
+                entries.push(Id {
+                    entries: [value as u16; 16],
+                    len: 1,
+                    site: None,
+                });
+                result.push(Self { entries });
+            }
+
+            let remaining = n - placed;
+            if remaining > 0 {
+                // Not enough room here for the rest; partition onto the next depth.
+                let lower = result.last().cloned().unwrap_or_else(|| a.clone());
+                result.extend(Self::between_n_with_max(&lower, b, remaining, max));
+            }
+
+            return result;
+        }
+
+        Vec::new()
+    }
 }
 
 impl Id {
     fn new(value: u16) -> Self {
         Self {
-            entries: u16x16::splat(value),
+            entries: [value; 16],
             len: 1,
+            site: None,
         }
     }
 
     fn between_with_max(a: Self, b: Self, max: u16) -> Result<Self, ()> {
         debug_assert!(a < b);
-        let a = MASKS[a.len as usize - 1].select(u16x16::splat(0), a.entries);
-        let b = MASKS[b.len as usize - 1].select(u16x16::splat(max), b.entries);
-        let middle = a + ((b - a) / 2);
+        let a = masked(a.entries, a.len, 0);
+        let b = masked(b.entries, b.len, max);
+        let mut middle = [0u16; 16];
+        let mut grew = [false; 16];
+        for i in 0..16 {
+            middle[i] = a[i] + (b[i] - a[i]) / 2;
+            grew[i] = middle[i] > a[i];
+        }
         Ok(Id {
             entries: middle,
-            len: compute_len(middle.gt(a))?,
+            len: compute_len(grew)?,
+            site: None,
         })
     }
 
-    fn entries(&self) -> u16x16 {
-        MASKS[self.len as usize - 1].select(u16x16::splat(0), self.entries)
+    /// LSEQ boundary allocation for a single depth; `Err` means the
+    /// interval is too narrow and the caller should descend a level.
+    fn between_lseq<R: Rng>(
+        a: Self,
+        b: Self,
+        depth: usize,
+        rng: &mut R,
+        state: &mut LseqState,
+    ) -> Result<Self, ()> {
+        debug_assert!(a < b);
+        let a_val = a.entries()[0] as u32;
+        let b_val = b.entries()[0] as u32;
+        let interval = b_val - a_val;
+        if interval < 2 {
+            return Err(());
+        }
+
+        if state.boundaries.len() <= depth {
+            state.boundaries.resize(depth + 1, None);
+        }
+        let boundary = *state.boundaries[depth]
+            .get_or_insert_with(|| if rng.gen() { Boundary::Plus } else { Boundary::Minus });
+
+        let step = 1u32 << (LSEQ_START_BASE + depth as u32);
+        // `interval - 2`, not `interval - 1`: the offset must leave room for
+        // the `+ 1`/`- 1` step off the chosen edge, otherwise the result can
+        // land exactly on the opposite neighbor instead of strictly between.
+        let bound = step.min(interval - 2);
+        let offset = rng.gen_range(0..=bound);
+        let value = match boundary {
+            Boundary::Plus => a_val + 1 + offset,
+            Boundary::Minus => b_val - 1 - offset,
+        } as u16;
+
+        Ok(Id {
+            entries: [value; 16],
+            len: 1,
+            site: None,
+        })
+    }
+
+    fn entries(&self) -> [u16; 16] {
+        masked(self.entries, self.len, 0)
+    }
+
+    /// Whether `self` and `other` occupy the same digit, ignoring `site`.
+    /// Two ids racing on the same stale pair land here with equal position
+    /// but different sites, and that's not a real numeric gap to bisect.
+    fn same_position(&self, other: &Self) -> bool {
+        self.entries() == other.entries()
     }
 }
 
-fn compute_len(mask: m16x16) -> Result<u8, ()> {
-    for i in 0_u8..16_u8 {
-        if mask.extract(i as usize) {
-            return Ok(i + 1);
+fn compute_len(grew: [bool; 16]) -> Result<u8, ()> {
+    for (i, &grew) in grew.iter().enumerate() {
+        if grew {
+            return Ok(i as u8 + 1);
         }
     }
     Err(())
@@ -147,7 +381,7 @@ fn compute_len(mask: m16x16) -> Result<u8, ()> {
 
 impl PartialEq for Id {
     fn eq(&self, other: &Self) -> bool {
-        self.entries().eq(other.entries()).all()
+        self.entries() == other.entries() && self.site == other.site
     }
 }
 
@@ -155,13 +389,16 @@ impl Eq for Id {}
 
 impl PartialOrd for Id {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.entries().partial_cmp(&other.entries())
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Id {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        match self.entries().cmp(&other.entries()) {
+            Ordering::Equal => self.site.cmp(&other.site),
+            ordering => ordering,
+        }
     }
 }
 
@@ -169,7 +406,10 @@ impl fmt::Debug for Id {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let mut list = fmt.debug_list();
         for i in 0..self.len {
-            list.entry(&self.entries.extract(i as usize));
+            list.entry(&self.entries[i as usize]);
+        }
+        if let Some(site) = self.site {
+            list.entry(&site);
         }
         list.finish()
     }
@@ -179,7 +419,8 @@ impl fmt::Debug for Id {
 mod tests {
     extern crate rand;
 
-    use self::rand::{Rng, SeedableRng, StdRng};
+    use self::rand::rngs::StdRng;
+    use self::rand::{Rng, SeedableRng};
     use super::*;
 
     #[test]
@@ -187,10 +428,10 @@ mod tests {
         for seed in 0..50 {
             println!("Seed {:?}", seed);
             const MAX_VALUE: u16 = 2;
-            let mut rng = StdRng::from_seed(&[seed]);
+            let mut rng = StdRng::seed_from_u64(seed);
             let mut ids = vec![CompositeId::new(0), CompositeId::new(MAX_VALUE)];
             for _i in 0..200 {
-                let index = rng.gen_range::<usize>(1, ids.len());
+                let index = rng.gen_range(1..ids.len());
                 let middle = {
                     let left = &ids[index - 1];
                     let right = &ids[index];
@@ -205,15 +446,145 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_composite_id_generation_lseq() {
+        for seed in 0..50 {
+            println!("Seed {:?}", seed);
+            const MAX_VALUE: u16 = 1024;
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut state = LseqState::new();
+            let mut ids = vec![CompositeId::new(0), CompositeId::new(MAX_VALUE)];
+            for _i in 0..200 {
+                let index = rng.gen_range(1..ids.len());
+                let middle = {
+                    let left = &ids[index - 1];
+                    let right = &ids[index];
+                    CompositeId::between_with_strategy(
+                        left,
+                        right,
+                        MAX_VALUE,
+                        Strategy::Lseq,
+                        &mut rng,
+                        &mut state,
+                    )
+                };
+                ids.insert(index, middle);
+
+                let mut sorted_ids = ids.clone();
+                sorted_ids.sort();
+                assert_eq!(ids, sorted_ids);
+            }
+        }
+    }
+
+    #[test]
+    fn test_composite_id_batch_generation() {
+        for seed in 0..50 {
+            println!("Seed {:?}", seed);
+            const MAX_VALUE: u16 = 256;
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut ids = vec![CompositeId::new(0), CompositeId::new(MAX_VALUE)];
+            for _i in 0..20 {
+                let index = rng.gen_range(1..ids.len());
+                let n = rng.gen_range(1..20);
+                let batch = {
+                    let left = &ids[index - 1];
+                    let right = &ids[index];
+                    CompositeId::between_n_with_max(left, right, n, MAX_VALUE)
+                };
+                assert_eq!(batch.len(), n);
+                for pair in batch.windows(2) {
+                    assert!(pair[0] < pair[1]);
+                }
+                for (offset, id) in batch.into_iter().enumerate() {
+                    ids.insert(index + offset, id);
+                }
+
+                let mut sorted_ids = ids.clone();
+                sorted_ids.sort();
+                assert_eq!(ids, sorted_ids);
+            }
+        }
+    }
+
+    #[test]
+    fn test_composite_id_generation_with_replicas() {
+        for seed in 0..50 {
+            println!("Seed {:?}", seed);
+            const MAX_VALUE: u16 = 2;
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut replica_a = Replica::new(1);
+            let mut replica_b = Replica::new(2);
+            let mut ids = vec![CompositeId::new(0), CompositeId::new(MAX_VALUE)];
+            for _i in 0..100 {
+                let index = rng.gen_range(1..ids.len());
+                // Both replicas compute against the same stale pair, as if
+                // read before either insert landed, so they can collide.
+                let left = ids[index - 1].clone();
+                let right = ids[index].clone();
+
+                let mut mids = vec![
+                    CompositeId::between_with_max_for_replica(&left, &right, MAX_VALUE, &mut replica_a),
+                    CompositeId::between_with_max_for_replica(&left, &right, MAX_VALUE, &mut replica_b),
+                ];
+                mids.sort();
+                assert_ne!(mids[0], mids[1]);
+                for (offset, mid) in mids.into_iter().enumerate() {
+                    ids.insert(index + offset, mid);
+                }
+
+                let mut sorted_ids = ids.clone();
+                sorted_ids.sort();
+                assert_eq!(ids, sorted_ids);
+
+                let mut deduped = ids.clone();
+                deduped.dedup();
+                assert_eq!(deduped.len(), ids.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_order_preserving_bytes() {
+        for seed in 0..50 {
+            println!("Seed {:?}", seed);
+            const MAX_VALUE: u16 = 4;
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut replica = Replica::new(7);
+            let mut ids = vec![CompositeId::new(0), CompositeId::new(MAX_VALUE)];
+            for _i in 0..50 {
+                let index = rng.gen_range(1..ids.len());
+                let middle = {
+                    let left = &ids[index - 1];
+                    let right = &ids[index];
+                    CompositeId::between_with_max_for_replica(left, right, MAX_VALUE, &mut replica)
+                };
+                ids.insert(index, middle);
+            }
+
+            for id in &ids {
+                assert_eq!(CompositeId::from_bytes(&id.to_order_preserving_bytes()), *id);
+            }
+
+            let bytes: Vec<Vec<u8>> = ids
+                .iter()
+                .map(CompositeId::to_order_preserving_bytes)
+                .collect();
+            let mut sorted_bytes = bytes.clone();
+            sorted_bytes.sort();
+            assert_eq!(bytes, sorted_bytes);
+        }
+    }
+
     #[test]
     fn test_primitive_id_generation() {
         for seed in 0..100 {
             println!("Seed {:?}", seed);
             const MAX_VALUE: u16 = 4;
-            let mut rng = StdRng::from_seed(&[seed]);
+            let mut rng = StdRng::seed_from_u64(seed);
             let mut ids = vec![Id::new(0), Id::new(MAX_VALUE)];
             for _i in 0..50 {
-                let index = rng.gen_range::<usize>(1, ids.len());
+                let index = rng.gen_range(1..ids.len());
                 let left = ids[index - 1];
                 let right = ids[index];
                 ids.insert(index, Id::between_with_max(left, right, MAX_VALUE).unwrap());